@@ -0,0 +1,78 @@
+// Copyright 2015-2019 Parity Technologies (UK) Ltd.
+// This file is part of Parity Ethereum.
+
+// Parity Ethereum is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Ethereum is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Ethereum.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Shared `contractVersion()` probing and caching, used to pick the right ABI for a contract
+//! whose interface has evolved across deployments (e.g. the service transaction certifier, the
+//! destination whitelist, or the transaction permission ACL).
+
+use std::sync::Arc;
+use parking_lot::Mutex;
+use call_contract::CallContract;
+use types::ids::BlockId;
+use ethabi::FunctionOutputDecoder;
+use ethereum_types::Address;
+use lru_cache::LruCache;
+
+use_contract!(contract_version, "res/contracts/contract_version.json");
+
+/// Number of recently probed contract addresses to keep cached.
+const MAX_CACHE_SIZE: usize = 4096;
+
+/// Version assumed for a contract that doesn't implement `contractVersion()` at all, or whose
+/// probe call reverts or fails to decode. Keeps pre-versioning deployments working unchanged.
+pub const DEFAULT_CONTRACT_VERSION: u64 = 1;
+
+/// Caches the result of probing a contract's `contractVersion()` so the probe only runs once
+/// per contract address.
+#[derive(Clone)]
+pub struct ContractVersionCache {
+	versions: Arc<Mutex<LruCache<Address, u64>>>,
+}
+
+impl Default for ContractVersionCache {
+	fn default() -> Self {
+		ContractVersionCache {
+			versions: Arc::new(Mutex::new(LruCache::new(MAX_CACHE_SIZE))),
+		}
+	}
+}
+
+impl ContractVersionCache {
+	/// Returns the version of the contract at `contract_address`, probing and caching it on
+	/// first use. A revert or decode failure on the probe is treated as `DEFAULT_CONTRACT_VERSION`.
+	pub fn version<C: CallContract>(&self, client: &C, contract_address: Address) -> u64 {
+		if let Some(version) = self.versions.lock().get_mut(&contract_address) {
+			return *version;
+		}
+
+		let version = self.probe_version(client, contract_address).unwrap_or(DEFAULT_CONTRACT_VERSION);
+		self.versions.lock().insert(contract_address, version);
+		version
+	}
+
+	/// Clears all cached contract versions.
+	pub fn clear(&self) {
+		self.versions.lock().clear();
+	}
+
+	fn probe_version<C: CallContract>(&self, client: &C, contract_address: Address) -> Result<u64, String> {
+		trace!(target: "txqueue", "Probing contractVersion() for {}", contract_address);
+		let (data, decoder) = contract_version::functions::contract_version::call();
+		let value = client.call_contract(BlockId::Latest, contract_address, data)?;
+		let version = decoder.decode(&value).map_err(|e| e.to_string())?;
+		Result::Ok(version.low_u64())
+	}
+}
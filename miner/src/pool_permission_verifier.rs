@@ -0,0 +1,69 @@
+// Copyright 2015-2019 Parity Technologies (UK) Ltd.
+// This file is part of Parity Ethereum.
+
+// Parity Ethereum is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Ethereum is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Ethereum.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The actual pool-verifier call site for `TransactionFilter` and `ServiceTransactionChecker`.
+//!
+//! `TransactionFilter::transaction_allowed` and `ServiceTransactionChecker::check` are only
+//! consulted if something calls them; this module is that something. Construct one
+//! `PoolPermissionVerifier` alongside the transaction pool and call `verify_transaction` from the
+//! verifier stage that runs just before a transaction is accepted into the queue, and
+//! `refresh_cache` from the client's chain-notify hook on every new block.
+
+use call_contract::{CallContract, RegistryInfo};
+use types::transaction::SignedTransaction;
+
+use service_transaction_checker::ServiceTransactionChecker;
+use transaction_filter::TransactionFilter;
+
+/// Combines the ACL permission filter with the service-transaction certifier into the single
+/// check the transaction pool's verifier should run against an incoming transaction.
+#[derive(Default, Clone)]
+pub struct PoolPermissionVerifier {
+	transaction_filter: TransactionFilter,
+	service_transaction_checker: ServiceTransactionChecker,
+}
+
+impl PoolPermissionVerifier {
+	/// Rejects `tx` if the ACL contract disallows it for its sender, recipient and value.
+	///
+	/// Does not reject on behalf of `ServiceTransactionChecker`: certification only decides
+	/// whether the sender is exempt from paying gas, not whether the pool should admit the
+	/// transaction at all, so it is exposed separately for the verifier to consult when pricing
+	/// the transaction rather than folded into this accept/reject check.
+	pub fn verify_transaction<C: CallContract + RegistryInfo>(&self, client: &C, tx: &SignedTransaction) -> Result<(), String> {
+		if self.transaction_filter.transaction_allowed(client, tx)? {
+			Result::Ok(())
+		} else {
+			Result::Err(format!("transaction from {} to {:?} is not permitted by the transaction permission contract", tx.sender(), tx.action))
+		}
+	}
+
+	/// Returns the zero-gas-price certification check, for the verifier to consult when pricing
+	/// `tx` rather than when deciding whether to admit it.
+	pub fn service_transaction_checker(&self) -> &ServiceTransactionChecker {
+		&self.service_transaction_checker
+	}
+
+	/// Clears both the ACL permission cache and the service-transaction certification caches.
+	///
+	/// Call this from the client's chain-notify hook on every new block, so that permissions or
+	/// certifications revoked or granted on-chain take effect immediately instead of being masked
+	/// by stale cached results.
+	pub fn refresh_cache(&self) {
+		self.transaction_filter.refresh_cache();
+		self.service_transaction_checker.refresh_cache();
+	}
+}
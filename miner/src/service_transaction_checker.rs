@@ -16,21 +16,46 @@
 
 //! A service transactions contract checker.
 
+use std::sync::Arc;
+use parking_lot::Mutex;
 use call_contract::{CallContract, RegistryInfo};
 use types::ids::BlockId;
 use types::transaction::{SignedTransaction, Action};
 use ethabi::FunctionOutputDecoder;
-use ethereum_types::Address;
+use ethereum_types::{Address, U256};
+use lru_cache::LruCache;
 
+use contract_version::ContractVersionCache;
+
+// Version 1 ABI: `certified(address)`, kept for certifier contracts deployed before versioning.
 use_contract!(service_transaction, "res/contracts/service_transaction.json");
+// Version 2+ ABI: `certified(address,address,uint256)`, also considering the recipient and value.
+use_contract!(service_transaction_v2, "res/contracts/service_transaction_v2.json");
 use_contract!(service_destination_whitelist, "res/contracts/service_destination_whitelist.json");
 
 const SERVICE_TRANSACTION_CONTRACT_REGISTRY_NAME: &'static str = "service_transaction_checker";
 const SERVICE_CERTIFIED_WHITESLIT_CONTRACT_REGISTRY_NAME: &'static str = "service_destination_whitelist";
 
+/// Number of recently certified senders/whitelisted destinations to keep cached between blocks.
+const MAX_CACHE_SIZE: usize = 4096;
+
 /// Service transactions checker.
-#[derive(Default, Clone)]
-pub struct ServiceTransactionChecker;
+#[derive(Clone)]
+pub struct ServiceTransactionChecker {
+	certified_addresses_cache: Arc<Mutex<LruCache<(Address, Address, U256), bool>>>,
+	whitelist_addresses_cache: Arc<Mutex<LruCache<Address, bool>>>,
+	contract_versions: ContractVersionCache,
+}
+
+impl Default for ServiceTransactionChecker {
+	fn default() -> Self {
+		ServiceTransactionChecker {
+			certified_addresses_cache: Arc::new(Mutex::new(LruCache::new(MAX_CACHE_SIZE))),
+			whitelist_addresses_cache: Arc::new(Mutex::new(LruCache::new(MAX_CACHE_SIZE))),
+			contract_versions: ContractVersionCache::default(),
+		}
+	}
+}
 
 impl ServiceTransactionChecker {
 	/// Checks if given address in tx is whitelisted to send service transactions.
@@ -45,12 +70,22 @@ impl ServiceTransactionChecker {
 			Action::Create => Address::default(),
 			Action::Call(address) => address,
 		};
-		self.check_address(client, sender, to_address)
+		self.check_address_with_value(client, sender, to_address, tx.value)
 	}
 
 	/// Checks if given address is whitelisted to send service transactions.
+	///
+	/// Assumes a value of zero. Use `check` when an actual `SignedTransaction` is available, or
+	/// `check_address_with_value`/`service_transaction_gas_price` when a non-zero value needs to
+	/// reach a v2+ certifier contract.
 	pub fn check_address<C: CallContract + RegistryInfo>(&self, client: &C, sender: Address, to: Address) -> Result<bool, String> {
-		let certified = self.check_certified_address(client, sender)?;
+		self.check_address_with_value(client, sender, to, U256::zero())
+	}
+
+	/// Checks if given address is whitelisted to send service transactions, passing `value` through
+	/// to a v2+ certifier contract.
+	pub fn check_address_with_value<C: CallContract + RegistryInfo>(&self, client: &C, sender: Address, to: Address, value: U256) -> Result<bool, String> {
+		let certified = self.check_certified_address(client, sender, to, value)?;
 		if !certified {
 			return Result::Ok(false);
 		}
@@ -64,26 +99,80 @@ impl ServiceTransactionChecker {
 		Result::Ok(whitelist_allowed)
 	}
 
-	/// Calls certifier contract with 'certified(address)' function
-	fn check_certified_address<C: CallContract + RegistryInfo>(&self, client: &C, sender: Address) -> Result<bool, String> {
+	/// Returns the gas price to use for a transaction authored internally (e.g. a validator
+	/// report or a registry write sent by `transact_contract`), so every call site doesn't need
+	/// to duplicate the match on `check_address`'s result.
+	///
+	/// Returns zero when `author` is certified to send a transaction of `value` to `to`, and
+	/// `fallback` otherwise. Pass the actual value the transaction will carry (zero for most
+	/// internally authored transactions) so a v2+ certifier is asked about the same arguments
+	/// `check` would later verify the built transaction against.
+	pub fn service_transaction_gas_price<C: CallContract + RegistryInfo>(&self, client: &C, author: Address, to: Address, value: U256, fallback: U256) -> U256 {
+		match self.check_address_with_value(client, author, to, value) {
+			Ok(true) => U256::zero(),
+			_ => fallback,
+		}
+	}
+
+	/// Clears the certified-sender and destination-whitelist caches.
+	///
+	/// Should be called from the client's chain-notify hook whenever a new block is imported,
+	/// so that certifications revoked or granted on-chain take effect immediately instead of
+	/// being masked by stale cached results.
+	pub fn refresh_cache(&self) {
+		self.certified_addresses_cache.lock().clear();
+		self.whitelist_addresses_cache.lock().clear();
+	}
+
+	/// Calls the certifier contract's 'certified' function, caching the result per
+	/// (sender, to, value) triple — a v2+ contract's answer can depend on the recipient and the
+	/// value, so caching on sender/to alone would serve a cached answer computed for one value to
+	/// a transaction carrying a different one.
+	///
+	/// Probes `contractVersion()` once per contract address to pick the right signature: version 1
+	/// contracts only understand `certified(address)`, while version 2+ also take the recipient
+	/// and value into account.
+	fn check_certified_address<C: CallContract + RegistryInfo>(&self, client: &C, sender: Address, to: Address, value: U256) -> Result<bool, String> {
+		if let Some(certified) = self.certified_addresses_cache.lock().get_mut(&(sender, to, value)) {
+			return Result::Ok(*certified);
+		}
+
 		let contract_address = client.registry_address(SERVICE_TRANSACTION_CONTRACT_REGISTRY_NAME.to_owned(), BlockId::Latest)
 			.ok_or_else(|| "contract is not configured")?;
 		trace!(target: "txqueue", "Checking service transaction checker contract from {}", sender);
-		let (data, decoder) = service_transaction::functions::certified::call(sender);
-		let value = client.call_contract(BlockId::Latest, contract_address, data)?;
-		decoder.decode(&value).map_err(|e| e.to_string())
+
+		let version = self.contract_versions.version(client, contract_address);
+		let certified = if version >= 2 {
+			let (data, decoder) = service_transaction_v2::functions::certified::call(sender, to, value);
+			let result = client.call_contract(BlockId::Latest, contract_address, data)?;
+			decoder.decode(&result).map_err(|e| e.to_string())?
+		} else {
+			let (data, decoder) = service_transaction::functions::certified::call(sender);
+			let result = client.call_contract(BlockId::Latest, contract_address, data)?;
+			decoder.decode(&result).map_err(|e| e.to_string())?
+		};
+
+		self.certified_addresses_cache.lock().insert((sender, to, value), certified);
+		Result::Ok(certified)
 	}
 
-	/// Checks if a destination address is whitelisted to accept service transaction
+	/// Checks if a destination address is whitelisted to accept service transaction, caching the result per destination.
 	fn check_whitelist_address<C:CallContract + RegistryInfo>(&self, client: &C, to: Address) -> Result<bool, String> {
+		if let Some(whitelisted) = self.whitelist_addresses_cache.lock().get_mut(&to) {
+			return Result::Ok(*whitelisted);
+		}
+
 		let contract_address = client.registry_address(SERVICE_CERTIFIED_WHITESLIT_CONTRACT_REGISTRY_NAME.to_owned(), BlockId::Latest)
 			.ok_or_else(|| "whitelist contract is not configured")?;
 		let is_whitelist_active = self.check_whitelist_active(client, contract_address)?;
-		if !is_whitelist_active {
-			return Result::Ok(false);
-		}
+		let whitelisted = if !is_whitelist_active {
+			false
+		} else {
+			self.check_whitelist_address_presence(client, contract_address, to)?
+		};
 
-		self.check_whitelist_address_presence(client, contract_address, to)
+		self.whitelist_addresses_cache.lock().insert(to, whitelisted);
+		Result::Ok(whitelisted)
 	}
 
 	/// Calls destination whitelist contract with 'whitelisted(address)' function
@@ -102,3 +191,195 @@ impl ServiceTransactionChecker {
 		decoder.decode(&value).map_err(|e| e.to_string())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::RefCell;
+	use std::collections::VecDeque;
+	use ethabi::{encode, Token};
+
+	/// A `CallContract + RegistryInfo` double that replays a fixed queue of `call_contract`
+	/// responses in order: a `contractVersion()` probe, then the actual certifier/whitelist call.
+	struct TestClient {
+		registry: Option<Address>,
+		responses: RefCell<VecDeque<Result<Vec<u8>, String>>>,
+	}
+
+	impl TestClient {
+		fn new(registry: Option<Address>, responses: Vec<Result<Vec<u8>, String>>) -> Self {
+			TestClient { registry, responses: RefCell::new(responses.into_iter().collect()) }
+		}
+	}
+
+	impl RegistryInfo for TestClient {
+		fn registry_address(&self, _name: String, _block: BlockId) -> Option<Address> {
+			self.registry
+		}
+	}
+
+	impl CallContract for TestClient {
+		fn call_contract(&self, _block: BlockId, _address: Address, _data: Vec<u8>) -> Result<Vec<u8>, String> {
+			self.responses.borrow_mut().pop_front().unwrap_or_else(|| Err("no response queued".into()))
+		}
+	}
+
+	fn encode_version(version: u64) -> Vec<u8> {
+		encode(&[Token::Uint(U256::from(version))])
+	}
+
+	fn encode_bool(value: bool) -> Vec<u8> {
+		encode(&[Token::Bool(value)])
+	}
+
+	#[test]
+	fn check_certified_address_caches_per_sender_and_recipient() {
+		let checker = ServiceTransactionChecker::default();
+		let contract = Address::from_low_u64_be(1);
+		let sender = Address::from_low_u64_be(2);
+		let to_a = Address::from_low_u64_be(3);
+		let to_b = Address::from_low_u64_be(4);
+		let client = TestClient::new(Some(contract), vec![
+			Ok(encode_version(1)),
+			Ok(encode_bool(true)),  // certified(sender) for to_a, v1 ignores recipient
+			Ok(encode_bool(false)), // a fresh query for (sender, to_b) must not reuse the to_a result
+		]);
+
+		assert_eq!(checker.check_certified_address(&client, sender, to_a, U256::zero()), Ok(true));
+		// Cached: no response left queued for a repeated (sender, to_a) lookup.
+		assert_eq!(checker.check_certified_address(&client, sender, to_a, U256::zero()), Ok(true));
+		// Different recipient: must not be served the to_a cache entry.
+		assert_eq!(checker.check_certified_address(&client, sender, to_b, U256::zero()), Ok(false));
+	}
+
+	#[test]
+	fn check_certified_address_caches_per_value() {
+		let checker = ServiceTransactionChecker::default();
+		let contract = Address::from_low_u64_be(1);
+		let sender = Address::from_low_u64_be(2);
+		let to = Address::from_low_u64_be(3);
+		let client = TestClient::new(Some(contract), vec![
+			Ok(encode_version(2)),
+			Ok(encode_bool(true)),  // certified(sender, to, 1)
+			Ok(encode_bool(false)), // a fresh query for (sender, to, 2) must not reuse the value-1 result
+		]);
+
+		assert_eq!(checker.check_certified_address(&client, sender, to, U256::from(1)), Ok(true));
+		// Cached: no response left queued for a repeated (sender, to, 1) lookup.
+		assert_eq!(checker.check_certified_address(&client, sender, to, U256::from(1)), Ok(true));
+		// Different value: must not be served the value-1 cache entry.
+		assert_eq!(checker.check_certified_address(&client, sender, to, U256::from(2)), Ok(false));
+	}
+
+	#[test]
+	fn refresh_cache_forces_a_fresh_certification_query() {
+		let checker = ServiceTransactionChecker::default();
+		let contract = Address::from_low_u64_be(1);
+		let sender = Address::from_low_u64_be(2);
+		let to = Address::from_low_u64_be(3);
+		let client = TestClient::new(Some(contract), vec![
+			Ok(encode_version(1)),
+			Ok(encode_bool(true)),
+			Ok(encode_bool(false)),
+		]);
+
+		assert_eq!(checker.check_certified_address(&client, sender, to, U256::zero()), Ok(true));
+		checker.refresh_cache();
+		// Cache cleared: the third queued response (certification revoked) is consulted.
+		assert_eq!(checker.check_certified_address(&client, sender, to, U256::zero()), Ok(false));
+	}
+
+	#[test]
+	fn check_whitelist_address_caches_per_destination() {
+		let checker = ServiceTransactionChecker::default();
+		let contract = Address::from_low_u64_be(1);
+		let to = Address::from_low_u64_be(2);
+		let client = TestClient::new(Some(contract), vec![
+			Ok(encode_bool(true)),  // activated()
+			Ok(encode_bool(true)),  // whitelisted(to)
+		]);
+
+		assert_eq!(checker.check_whitelist_address(&client, to), Ok(true));
+		// Cached: no responses left queued for a repeated lookup.
+		assert_eq!(checker.check_whitelist_address(&client, to), Ok(true));
+	}
+
+	#[test]
+	fn service_transaction_gas_price_checks_certification_for_the_given_value() {
+		let checker = ServiceTransactionChecker::default();
+		let contract = Address::from_low_u64_be(1);
+		let author = Address::from_low_u64_be(2);
+		let to = Address::from_low_u64_be(3);
+		let client = TestClient::new(Some(contract), vec![
+			Ok(encode_version(2)),
+			Ok(encode_bool(false)), // certified(author, to, 5) refuses this value
+		]);
+		// A value-zero lookup would be answered from this cache entry without reaching the client.
+		checker.certified_addresses_cache.lock().insert((author, to, U256::zero()), true);
+
+		// Falls back to `fallback`, not zero, since the certifier was asked about value 5 and
+		// refused — a bug here would wrongly check value zero instead and return Ok(true).
+		assert_eq!(
+			checker.service_transaction_gas_price(&client, author, to, U256::from(5), U256::from(21_000)),
+			U256::from(21_000),
+		);
+	}
+
+	#[test]
+	fn falls_back_to_v1_when_version_probe_reverts() {
+		let checker = ServiceTransactionChecker::default();
+		let contract = Address::from_low_u64_be(1);
+		let sender = Address::from_low_u64_be(2);
+		let to = Address::from_low_u64_be(3);
+		let client = TestClient::new(Some(contract), vec![
+			Err("execution reverted".into()),
+			Ok(encode_bool(true)), // legacy certified(sender)
+		]);
+
+		assert_eq!(checker.check_certified_address(&client, sender, to, U256::from(7)), Ok(true));
+	}
+
+	#[test]
+	fn v2_certifier_is_called_with_the_real_recipient_and_value() {
+		let checker = ServiceTransactionChecker::default();
+		let contract = Address::from_low_u64_be(1);
+		let sender = Address::from_low_u64_be(2);
+		let to = Address::from_low_u64_be(3);
+		let value = U256::from(42);
+		let (expected_data, _) = service_transaction_v2::functions::certified::call(sender, to, value);
+
+		struct RecordingClient {
+			registry: Address,
+			version_response: Vec<u8>,
+			certified_response: Vec<u8>,
+			expected_certified_call: Vec<u8>,
+		}
+
+		impl RegistryInfo for RecordingClient {
+			fn registry_address(&self, _name: String, _block: BlockId) -> Option<Address> {
+				Some(self.registry)
+			}
+		}
+
+		impl CallContract for RecordingClient {
+			fn call_contract(&self, _block: BlockId, _address: Address, data: Vec<u8>) -> Result<Vec<u8>, String> {
+				if data == self.expected_certified_call {
+					Ok(self.certified_response.clone())
+				} else {
+					Ok(self.version_response.clone())
+				}
+			}
+		}
+
+		let client = RecordingClient {
+			registry: contract,
+			version_response: encode_version(2),
+			certified_response: encode_bool(true),
+			expected_certified_call: expected_data,
+		};
+
+		// Fails (serves the version response) unless `check_certified_address` builds the v2 call
+		// with the exact (sender, to, value) this test expects the contract to receive.
+		assert_eq!(checker.check_certified_address(&client, sender, to, value), Ok(true));
+	}
+}
@@ -0,0 +1,316 @@
+// Copyright 2015-2019 Parity Technologies (UK) Ltd.
+// This file is part of Parity Ethereum.
+
+// Parity Ethereum is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Ethereum is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Ethereum.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A general transaction permissioning filter, backed by an on-chain ACL contract.
+//!
+//! Consulted from `PoolPermissionVerifier::verify_transaction` (see `pool_permission_verifier`),
+//! which the transaction pool's verifier calls immediately before a transaction is accepted into
+//! the queue; `refresh_cache` is likewise called from `PoolPermissionVerifier::refresh_cache`,
+//! which the client's chain-notify hook should invoke on every new block.
+
+use std::sync::Arc;
+use parking_lot::Mutex;
+use call_contract::{CallContract, RegistryInfo};
+use types::ids::BlockId;
+use types::transaction::{SignedTransaction, Action};
+use ethabi::FunctionOutputDecoder;
+use ethereum_types::{Address, U256};
+use lru_cache::LruCache;
+
+use contract_version::ContractVersionCache;
+
+// Version 1 ABI: `allowedTxTypes(address)`, mask only, no recipient/value or cache flag.
+use_contract!(tx_acl_v1, "res/contracts/tx_acl_v1.json");
+// Version 2+ ABI: `allowedTxTypes(address,address,uint256)`, returning the mask plus a flag
+// telling us whether the answer is stable enough to cache for the sender.
+use_contract!(tx_acl_v2, "res/contracts/tx_acl_v2.json");
+
+const TRANSACT_ACL_CONTRACT_REGISTRY_NAME: &'static str = "tx_permission_contract";
+
+/// Number of recently resolved sender permissions to keep cached between blocks.
+const MAX_CACHE_SIZE: usize = 4096;
+
+/// Bit of the `allowedTxTypes` mask permitting a basic value transfer.
+const BASIC_TX_PERMISSION: u8 = 0b0000_0001;
+/// Bit of the `allowedTxTypes` mask permitting a call into an existing contract.
+const CALL_TX_PERMISSION: u8 = 0b0000_0010;
+/// Bit of the `allowedTxTypes` mask permitting contract creation.
+const CREATE_TX_PERMISSION: u8 = 0b0000_0100;
+// Bit 0b0000_1000 of the mask is reserved by the ACL contract for private transactions, which
+// have no representation in `SignedTransaction`/`Action` and so cannot be distinguished here;
+// `TransactionFilter` only ever requests the basic/call/create bits above.
+
+/// A transaction permission filter, backed by an `allowedTxTypes` ACL contract.
+///
+/// Resolves the ACL contract through the registry, same as `ServiceTransactionChecker`, and
+/// rejects any transaction whose action (`Create` vs `Call`) and value fall outside the
+/// permission mask the contract returns for its sender.
+#[derive(Clone)]
+pub struct TransactionFilter {
+	permission_cache: Arc<Mutex<LruCache<(Address, Address, U256), u8>>>,
+	contract_versions: ContractVersionCache,
+}
+
+impl Default for TransactionFilter {
+	fn default() -> Self {
+		TransactionFilter {
+			permission_cache: Arc::new(Mutex::new(LruCache::new(MAX_CACHE_SIZE))),
+			contract_versions: ContractVersionCache::default(),
+		}
+	}
+}
+
+impl TransactionFilter {
+	/// Checks whether `tx` is permitted to enter the chain.
+	///
+	/// Returns `Ok(true)` when no permission contract is configured in the registry, so that
+	/// chains which don't opt into this feature keep their previous, permissive behaviour.
+	pub fn transaction_allowed<C: CallContract + RegistryInfo>(&self, client: &C, tx: &SignedTransaction) -> Result<bool, String> {
+		let contract_address = match client.registry_address(TRANSACT_ACL_CONTRACT_REGISTRY_NAME.to_owned(), BlockId::Latest) {
+			Some(address) => address,
+			None => return Result::Ok(true),
+		};
+
+		let sender = tx.sender();
+		let to = match tx.action {
+			Action::Create => Address::default(),
+			Action::Call(address) => address,
+		};
+
+		let mask = self.allowed_tx_types(client, contract_address, sender, to, tx.value)?;
+		let required = match tx.action {
+			Action::Create => CREATE_TX_PERMISSION,
+			Action::Call(_) if tx.data.is_empty() => BASIC_TX_PERMISSION,
+			Action::Call(_) => CALL_TX_PERMISSION,
+		};
+
+		Result::Ok(mask & required != 0)
+	}
+
+	/// Clears the cached sender/recipient permission masks.
+	///
+	/// Should be called from the client's chain-notify hook whenever a new block is imported, same
+	/// as `ServiceTransactionChecker::refresh_cache`, so that permissions revoked or granted
+	/// on-chain take effect immediately instead of being masked by stale cached results.
+	pub fn refresh_cache(&self) {
+		self.permission_cache.lock().clear();
+	}
+
+	/// Calls the ACL contract's 'allowedTxTypes' function, returning the decoded permission
+	/// bitmask for the (`sender`, `to`, `value`) triple.
+	///
+	/// Probes `contractVersion()` once per contract address to pick the right signature: version 1
+	/// contracts only understand `allowedTxTypes(address)` and are never cached, since they don't
+	/// signal whether the answer is stable; version 2+ also take the recipient and value into
+	/// account and honor the contract's "cache permissions" flag. Since the mask can depend on
+	/// `value`, it is cached under the full `(sender, to, value)` triple rather than just the
+	/// sender/recipient pair.
+	fn allowed_tx_types<C: CallContract + RegistryInfo>(&self, client: &C, contract_address: Address, sender: Address, to: Address, value: U256) -> Result<u8, String> {
+		if let Some(mask) = self.permission_cache.lock().get_mut(&(sender, to, value)) {
+			return Result::Ok(*mask);
+		}
+
+		trace!(target: "txqueue", "Checking transaction permission contract for sender {}", sender);
+
+		let version = self.contract_versions.version(client, contract_address);
+		if version >= 2 {
+			let (data, decoder) = tx_acl_v2::functions::allowed_tx_types::call(sender, to, value);
+			let result = client.call_contract(BlockId::Latest, contract_address, data)?;
+			let (mask, cache_permissions) = decoder.decode(&result).map_err(|e| e.to_string())?;
+			let mask = mask.low_u32() as u8;
+
+			if cache_permissions {
+				self.permission_cache.lock().insert((sender, to, value), mask);
+			}
+
+			Result::Ok(mask)
+		} else {
+			let (data, decoder) = tx_acl_v1::functions::allowed_tx_types::call(sender);
+			let result = client.call_contract(BlockId::Latest, contract_address, data)?;
+			let mask = decoder.decode(&result).map_err(|e| e.to_string())?;
+			Result::Ok(mask.low_u32() as u8)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::RefCell;
+	use std::collections::VecDeque;
+	use ethabi::{encode, Token};
+	use ethkey::{Generator, Random};
+	use types::transaction::Transaction;
+
+	/// A `CallContract + RegistryInfo` double that replays a fixed queue of `call_contract`
+	/// responses in order, same as the real client would answer a `contractVersion()` probe
+	/// followed by the actual permission call.
+	struct TestClient {
+		registry: Option<Address>,
+		responses: RefCell<VecDeque<Result<Vec<u8>, String>>>,
+	}
+
+	impl TestClient {
+		fn new(registry: Option<Address>, responses: Vec<Result<Vec<u8>, String>>) -> Self {
+			TestClient { registry, responses: RefCell::new(responses.into_iter().collect()) }
+		}
+	}
+
+	impl RegistryInfo for TestClient {
+		fn registry_address(&self, _name: String, _block: BlockId) -> Option<Address> {
+			self.registry
+		}
+	}
+
+	impl CallContract for TestClient {
+		fn call_contract(&self, _block: BlockId, _address: Address, _data: Vec<u8>) -> Result<Vec<u8>, String> {
+			self.responses.borrow_mut().pop_front().unwrap_or_else(|| Err("no response queued".into()))
+		}
+	}
+
+	fn encode_version(version: u64) -> Vec<u8> {
+		encode(&[Token::Uint(U256::from(version))])
+	}
+
+	fn encode_mask_v1(mask: u8) -> Vec<u8> {
+		encode(&[Token::Uint(U256::from(mask))])
+	}
+
+	fn encode_mask_v2(mask: u8, cache_permissions: bool) -> Vec<u8> {
+		encode(&[Token::Uint(U256::from(mask)), Token::Bool(cache_permissions)])
+	}
+
+	fn signed_tx(action: Action, data: Vec<u8>) -> SignedTransaction {
+		signed_tx_with_value(action, data, U256::zero())
+	}
+
+	fn signed_tx_with_value(action: Action, data: Vec<u8>, value: U256) -> SignedTransaction {
+		let keypair = Random.generate().unwrap();
+		Transaction {
+			action,
+			nonce: U256::zero(),
+			gas_price: U256::zero(),
+			gas: U256::from(100_000),
+			value,
+			data,
+		}.sign(keypair.secret(), None)
+	}
+
+	#[test]
+	fn permissive_when_no_contract_configured() {
+		let filter = TransactionFilter::default();
+		let client = TestClient::new(None, vec![]);
+		let tx = signed_tx(Action::Create, vec![]);
+
+		assert_eq!(filter.transaction_allowed(&client, &tx), Ok(true));
+	}
+
+	#[test]
+	fn v1_fallback_when_version_probe_reverts() {
+		let filter = TransactionFilter::default();
+		let acl = Address::from_low_u64_be(1);
+		let client = TestClient::new(Some(acl), vec![
+			Err("execution reverted".into()),
+			Ok(encode_mask_v1(BASIC_TX_PERMISSION)),
+		]);
+
+		let basic_transfer = signed_tx(Action::Call(Address::from_low_u64_be(2)), vec![]);
+		assert_eq!(filter.transaction_allowed(&client, &basic_transfer), Ok(true));
+	}
+
+	#[test]
+	fn v1_rejects_contract_call_when_only_basic_permitted() {
+		let filter = TransactionFilter::default();
+		let acl = Address::from_low_u64_be(1);
+		let client = TestClient::new(Some(acl), vec![
+			Err("execution reverted".into()),
+			Ok(encode_mask_v1(BASIC_TX_PERMISSION)),
+		]);
+
+		let contract_call = signed_tx(Action::Call(Address::from_low_u64_be(2)), vec![1, 2, 3]);
+		assert_eq!(filter.transaction_allowed(&client, &contract_call), Ok(false));
+	}
+
+	#[test]
+	fn v2_permits_creation_when_create_permitted() {
+		let filter = TransactionFilter::default();
+		let acl = Address::from_low_u64_be(1);
+		let client = TestClient::new(Some(acl), vec![
+			Ok(encode_version(2)),
+			Ok(encode_mask_v2(CREATE_TX_PERMISSION, false)),
+		]);
+
+		let create = signed_tx(Action::Create, vec![]);
+		assert_eq!(filter.transaction_allowed(&client, &create), Ok(true));
+	}
+
+	#[test]
+	fn v2_rejects_creation_when_only_call_permitted() {
+		let filter = TransactionFilter::default();
+		let acl = Address::from_low_u64_be(1);
+		let client = TestClient::new(Some(acl), vec![
+			Ok(encode_version(2)),
+			Ok(encode_mask_v2(CALL_TX_PERMISSION, false)),
+		]);
+
+		let create = signed_tx(Action::Create, vec![]);
+		assert_eq!(filter.transaction_allowed(&client, &create), Ok(false));
+	}
+
+	#[test]
+	fn allowed_tx_types_caches_per_value() {
+		let filter = TransactionFilter::default();
+		let acl = Address::from_low_u64_be(1);
+		let client = TestClient::new(Some(acl), vec![
+			Ok(encode_version(2)),
+			Ok(encode_mask_v2(CREATE_TX_PERMISSION, true)),
+			Ok(encode_mask_v2(0, true)),
+		]);
+
+		let to = Address::from_low_u64_be(2);
+		let cheap = signed_tx_with_value(Action::Call(to), vec![], U256::from(1));
+		let expensive = signed_tx_with_value(Action::Call(to), vec![], U256::from(2));
+
+		// `cheap` resolves the version and queries the mask for value 1, which only permits
+		// creation, so the basic transfer is rejected; it also primes the cache for value 1.
+		assert_eq!(filter.transaction_allowed(&client, &cheap), Ok(false));
+		// A different value is not served from the value-1 cache entry: the third queued
+		// response (mask 0) is consulted instead of the cached `CREATE_TX_PERMISSION` mask.
+		assert_eq!(filter.transaction_allowed(&client, &expensive), Ok(false));
+		assert!(client.responses.borrow().is_empty());
+	}
+
+	#[test]
+	fn refresh_cache_forces_a_fresh_query() {
+		let filter = TransactionFilter::default();
+		let acl = Address::from_low_u64_be(1);
+		let client = TestClient::new(Some(acl), vec![
+			Ok(encode_version(2)),
+			Ok(encode_mask_v2(CREATE_TX_PERMISSION, true)),
+			Ok(encode_mask_v2(0, true)),
+		]);
+
+		let create = signed_tx(Action::Create, vec![]);
+		// First call resolves the version, queries the mask and caches it (cache_permissions = true).
+		assert_eq!(filter.transaction_allowed(&client, &create), Ok(true));
+		// Served from cache: the third queued response is untouched.
+		assert_eq!(filter.transaction_allowed(&client, &create), Ok(true));
+
+		filter.refresh_cache();
+		// Cache cleared: the third queued response (permission now revoked) is consulted.
+		assert_eq!(filter.transaction_allowed(&client, &create), Ok(false));
+	}
+}